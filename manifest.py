@@ -5,15 +5,23 @@ import os
 import re
 import pwd
 import sys
+import json
+import time
+import shlex
+import socket
+import platform
 sys.dont_write_bytecode = True
 
 from copy import deepcopy
+from collections import Counter
+from datetime import datetime
 from ruamel import yaml
 from pathlib import Path
 from fnmatch import fnmatch
+from tempfile import mkstemp
 from contextlib import contextmanager
 from subprocess import Popen, PIPE, CalledProcessError
-from argparse import ArgumentParser, Action
+from argparse import ArgumentParser, ArgumentTypeError, Action
 
 from leatherman.fuzzy import fuzzy
 from leatherman.repr import __repr__
@@ -26,17 +34,71 @@ if os.path.islink(__file__):
     LINK_NAME = REAL_NAME; REAL_NAME = os.path.basename(REAL_FILE)
     LINK_PATH = REAL_PATH; REAL_PATH = os.path.dirname(REAL_FILE)
 
-SECTIONS = [
-    'link',
-    'ppa',
+PKGMGRS = [
+    ('dpkg', 'deb'),
+    ('rpm', 'rpm'),
+    ('pacman', 'arch'),
+    ('brew', 'brew'),
+]
+
+# base-system packages every install already has; not worth seeding
+SEED_IGNORE = [
     'apt',
-    'dnf',
-    'npm',
-    'pip3',
-    'pipx',
-    'flatpak',
-    'github',
+    'base-files',
+    'bash',
+    'coreutils',
+    'dpkg',
+    'grub-*',
+    'init',
+    'linux-*',
+    'login',
+    'systemd*',
+    'ubuntu-*',
+]
+
+# package names are untrusted; they are limited to package spec characters and
+# shell-quoted on install lines. paths are trusted
+# templates that may carry $(...) through to the generated script
+PKG_PATTERN = re.compile(r'^[\w@.+:/=~,^#<>\[\]-]+$')
+PATH_UNSAFE = re.compile(r'[;&|<>`]')
+COMMIT_PATTERN = re.compile(r'^[0-9a-f]{40}$')
+ENV_PATTERN = re.compile(r'^[A-Za-z_][A-Za-z0-9_]*$')
+SOURCE_PATTERN = re.compile(r'^[\w.-]+$')
+
+FIELDS = dict(
+    vars=None,
+    verbose=None,
+    errors=None,
+    link=None,
+    ppa=['items', 'remove', 'when'],
+    pkg=['items'],
+    aliases=None,
+    order=None,
+    apt=['items', 'sources', 'autoremove', 'check', 'when'],
+    dnf=['items', 'autoremove', 'check', 'when'],
+    brew=['items', 'autoremove', 'check', 'when'],
+    pacman=['items', 'aur', 'check', 'when'],
+    npm=['items', 'from', 'check', 'when'],
+    pip3=['items', 'index_url', 'extra_index_url', 'check', 'when'],
+    pipx=['items', 'when'],
+    flatpak=['items', 'remote', 'runtimes', 'check', 'when'],
+    github=None,
+    script=None,
+)
+
+REPO_FIELDS = [
+    'link',
     'script',
+    'post_clone',
+    'enabled_if',
+    'clone_opts',
+    'sparse',
+    'commit',
+    'transport',
+    'env',
+    'group',
+    'inherit',
+    'token_env',
 ]
 
 UID = os.getuid()
@@ -56,19 +118,24 @@ linker() {
     file=$(realpath "$1")
     link="${2/#\~/$HOME}"
     echo "$link -> $file"
-    if [ -f "$link" ] && [ "$file" != "$(readlink $link)" ]; then
+    if [ -L "$link" ] && [ "$file" != "$(readlink "$link")" ]; then
+        echo "[retarget] $link -> $file"
+        ln -sfn "$file" "$link"
+        return
+    fi
+    if [ -f "$link" ] && [ "$file" != "$(readlink "$link")" ]; then
         orig="$link.orig"
         $VERBOSE && echo "backing up $orig"
-        mv $link $orig
+        mv "$link" "$orig"
     elif [ ! -f "$link" ] && [ -L "$link" ]; then
         $VERBOSE && echo "removing broken link $link"
-        unlink $link
+        unlink "$link"
     fi
     if [ -f "$link" ]; then
         echo "[exists] $link"
     else
         echo "[create] $link -> $file"
-        mkdir -p $(dirname $link); ln -s $file $link
+        mkdir -p "$(dirname "$link")"; ln -s "$file" "$link"
     fi
 }
 '''.lstrip('\n').rstrip()
@@ -79,12 +146,12 @@ latest() {
     LATEST="$2"
     NAME="${3:-"$PATTERN"}"
     URL="$(curl -sL "$LATEST" | jq -r ".assets[] | select(.name | test(\\"$PATTERN\\")) | .browser_download_url")"
-    FILENAME=$(basename $URL)
+    FILENAME=$(basename "$URL")
     TMPDIR=$(mkdir -p /tmp/manifest && mktemp -d /tmp/manifest/XXX)
-    pushd $TMPDIR
-    curl -sSL $URL -o $FILENAME
+    pushd "$TMPDIR"
+    curl -sSL "$URL" -o "$FILENAME"
     if [[ $FILENAME =~ \.tar\.gz ]]; then
-        tar xvf $FILENAME
+        tar xvf "$FILENAME"
         NAME=$(find . -name "$NAME")
     fi
     chmod a+x "$NAME" && cp "$NAME" ~/bin/
@@ -92,14 +159,131 @@ latest() {
 }
 '''.lstrip('\n').rstrip()
 
+SCRIPT_LOGS = '$HOME/.local/share/manifest/logs/scripts'
+
+REQUIRE_GIT = '''
+if ! hash git 2>/dev/null; then
+    echo "git is required to clone github repos; install it first (e.g. sudo apt install git)"
+    exit 1
+fi
+'''.lstrip('\n').rstrip()
+
+INIT = '''
+# manifest.yml: starter manifest written by manifest.py --init
+verbose: False
+errors: False
+# order: [github, script]
+# include: [common.yml]; paths are relative to this file and local keys win
+# {{name}} in scripts and link destinations is replaced at generation time
+# vars:
+#   version: 1.0.0
+
+link:
+  # recursive: True
+  home/.bashrc: $HOME/.bashrc
+  # etc/service.conf:
+  #   dst: /etc/service.conf
+  #   owner: root:root
+  #   copy: True
+  # bin:
+  #   base: $HOME/bin
+  #   sources: [bin/a, bin/b]
+
+ppa:
+  items: []
+
+pkg:
+  items:
+    - curl
+    - git
+    # skip the install when a binary is already on PATH
+    # - name: fd-find
+    #   bin: fdfind
+
+# aliases:
+#   ag:
+#     apt: silversearcher-ag
+#     dnf: the_silver_searcher
+#     brew: the_silver_searcher
+
+apt:
+  # when: distro==ubuntu && arch==x86_64; skip the section unless this holds
+  items:
+    - build-essential
+
+dnf:
+  items:
+    - gcc
+
+brew:
+  items: []
+
+pacman:
+  items: []
+  # aur: [yay-bin]
+
+npm:
+  items: []
+  # from: path/to/package.json/dir
+
+pip3:
+  items: []
+
+pipx:
+  items: []
+
+flatpak:
+  items: []
+  # runtimes: [org.gnome.Platform//45]
+
+github:
+  # repopath: repos
+  owner/repo:
+    # commit: <full sha>; checked out and verified instead of pulling
+    # transport: gh; clone with gh repo clone to reuse its auth
+    # env:
+    #   PKG_CONFIG_PATH: /usr/local/lib/pkgconfig
+    # post_clone: git-crypt unlock
+    link:
+      bin/tool: $HOME/bin/tool
+
+script:
+  hello: |
+    echo "hello from manifest"
+  # scripts run in a subshell; use the structured form to opt out
+  # profile:
+  #   isolated: False
+  #   requires: [apt]
+  #   run: |
+  #     export EDITOR=vim
+  # gitconfig:
+  #   write:
+  #     path: $HOME/.gitconfig.local
+  #     content: |
+  #       [user]
+  #           name = me
+'''.lstrip('\n')
+
 class UnknownPkgmgrError(Exception):
     def __init__(self):
         super(UnknownPkgmgrError, self).__init__('unknown pkgmgr!')
 
+def warn(message):
+    sys.stderr.write(f'warning: {message}\n')
+
 def expand(path):
     if path:
         return os.path.abspath(os.path.expanduser(path))
 
+def normalize(path):
+    if path:
+        return os.path.normpath(path)
+
+def rehome(path, home):
+    if home and home != HOME and path.startswith(HOME + os.sep):
+        return home + path[len(HOME):]
+    return path
+
 @contextmanager
 def cd(*args, **kwargs):
     mkdir = kwargs.pop('mkdir', True)
@@ -150,35 +334,167 @@ def check_hash(program):
     except CalledProcessError:
         return False
 
+def is_wsl():
+    try:
+        with open('/proc/version') as f:
+            return 'microsoft' in f.read().lower()
+    except IOError:
+        return False
+
+def resolve_winhome():
+    if not is_wsl():
+        return None
+    exitcode, stdout, _ = call('wslpath "$(cmd.exe /c "echo %USERPROFILE%" 2>/dev/null | tr -d \'\\r\')"', throw=False)
+    if not exitcode and stdout.strip():
+        return stdout.strip()
+    return f'/mnt/c/Users/{USER}'
+
+def resolve_home(username):
+    try:
+        return pwd.getpwnam(username).pw_dir
+    except KeyError:
+        sys.exit(f'unknown user: {username}')
+
+def git_describe():
+    try:
+        _, stdout, _ = call(f'git -C {REAL_PATH} describe --tags --always --dirty')
+        return stdout.strip()
+    except CalledProcessError:
+        return 'unknown'
+
 def get_pkgmgr():
-    if check_hash('dpkg'):
-        return 'deb'
-    elif check_hash('rpm'):
-        return 'rpm'
-    elif check_hash('brew'):
-        return 'brew'
+    for program, pkgmgr in PKGMGRS:
+        if check_hash(program):
+            return pkgmgr
     raise UnknownPkgmgrError
 
+def explain_pkgmgr():
+    rows = [('PROBE', 'FOUND', 'PKGMGR')]
+    selected = None
+    for program, pkgmgr in PKGMGRS:
+        found = check_hash(program)
+        rows += [(program, 'yes' if found else 'no', pkgmgr)]
+        if found and not selected:
+            selected = (program, pkgmgr)
+    if selected:
+        reason = f'selected: {selected[1]} (first probe found: {selected[0]})'
+    else:
+        reason = 'selected: none (no probe found; pass --pkgmgr explicitly)'
+    return tabulate(rows) + '\n\n' + reason
+
+def parse_showmanual(output):
+    items = [line.strip() for line in output.splitlines() if line.strip()]
+    return [item for item in items if not any(fnmatch(item, pattern) for pattern in SEED_IGNORE)]
+
+def seed_from_installed(pkgmgr):
+    if pkgmgr != 'apt':
+        sys.exit(f'unsupported pkgmgr for seeding: {pkgmgr}')
+    _, stdout, _ = call('apt-mark showmanual')
+    items = parse_showmanual(stdout)
+    return 'apt:\n  items:\n' + '\n'.join(f'    - {item}' for item in sorted(items))
+
 def render_item(item):
     if isinstance(item, tuple):
         return ' '.join(item)
     return str(item)
 
+def quote_path(path):
+    return '"' + re.sub(r'^~(?=/|$)', '$HOME', str(path)) + '"'
+
+def render_arg(item):
+    return shlex.quote(str(item))
+
+def delimiter(body, base='EOM'):
+    lines = body.split('\n')
+    result, count = base, 0
+    while result in lines:
+        count += 1
+        result = f'{base}_{count}'
+    return result
+
+def tabulate(rows, sep='  '):
+    widths = [max(len(str(row[i])) for row in rows) for i in range(len(rows[0]))]
+    return '\n'.join([
+        sep.join([str(cell).ljust(width) for cell, width in zip(row, widths)]).rstrip()
+        for row in rows
+    ])
+
+def validate_pkg(section, item):
+    if not PKG_PATTERN.match(str(item)):
+        sys.exit(f'unsafe {section} item: {item!r}')
+    return item
+
+def validate_path(path):
+    if PATH_UNSAFE.search(re.sub(r'\$\([^()]*\)', '', str(path))):
+        sys.exit(f'unsafe path: {path!r}; only $(...) substitutions are allowed')
+    return path
+
+def substitute(text, variables):
+    def replace(match):
+        name = match.group(1)
+        if name not in variables:
+            sys.exit(f'undefined var: {{{{{name}}}}}')
+        return str(variables[name])
+    return re.sub(r'\{\{\s*(\w+)\s*\}\}', replace, text)
+
+def select(items, patterns, exclude=None):
+    included = fuzzy(items).include(*patterns)
+    return fuzzy(included).exclude(*exclude) if exclude else included
+
+def duplicates(items):
+    return [item for item, count in Counter(items).items() if count > 1]
+
+def split_binary(item):
+    if isinstance(item, dict):
+        return item.get('name'), item.get('bin')
+    return item, None
+
 class ManifestType():
+    pkgmgr = None
+    tools = []
+
     def __repr__(self):
         return f'{type(self).__name__}(items = {self.items})'
 
     __str__ = __repr__
 
+    def names(self):
+        return [render_item(item) for item in self.items]
+
     def functions(self):
         return ''
 
+    def requirements(self):
+        return self.tools
+
     def render(self):
         raise NotImplementedError
 
 class PackageType(ManifestType):
-    def __init__(self, spec, patterns, **kwargs):
-        self.items = fuzzy(spec.get('items', {})).include(*patterns)
+    binaries = {}
+    maintenance = None
+    parallel = False
+    chunked = False
+    installed = None
+
+    def __init__(self, spec, patterns, dedup=False, section_parallel=None, chunk_size=None, exclude=None, check=False, **kwargs):
+        self.autoremove = spec.get('autoremove', False)
+        self.check = (spec.get('check', False) or check) and self.installed
+        self.section_parallel = section_parallel
+        self.chunk_size = chunk_size if self.chunked else None
+        section = type(self).__name__.lower()
+        items = [split_binary(item) for item in spec.get('items') or []]
+        selected = select([name for name, _ in items], patterns, exclude)
+        items = [
+            (validate_pkg(section, name), binary and validate_pkg(section, binary))
+            for name, binary in items if name in selected
+        ]
+        self.items = [name for name, _ in items]
+        self.binaries = {name: binary for name, binary in items if binary}
+        for item in duplicates(self.items):
+            warn(f'duplicate {type(self).__name__.lower()} item: {item}')
+        if dedup:
+            self.items = list(dict.fromkeys(self.items))
 
     def __repr__(self):
         return f'{type(self).__name__}(items={self.items})'
@@ -193,30 +509,90 @@ echo "{type(self).__name__.lower()}s:"
     def render_block(self):
         raise NotImplementedError
 
+    def render_maintenance(self):
+        if not (self.autoremove and self.maintenance):
+            return ''
+        return self.maintenance
+
 class HeredocPackageType(PackageType):
     def render_items(self):
-        return '\n'.join([render_item(item) for item in self.items])
+        return '\n'.join([
+            render_item((item, self.binaries[item]) if item in self.binaries else item)
+            for item in self.items
+        ])
+
+    def render_guard(self):
+        if not self.binaries:
+            return ''
+        return '''
+    [ -n "$bin" ] && command -v "$bin" >/dev/null && continue
+'''.lstrip('\n')
+
+    def render_jobs(self):
+        return self.parallel and self.items and self.section_parallel
+
+    def render_body(self):
+        block = self.render_block()
+        if not self.render_jobs():
+            return block
+        return f'''
+    {{
+{block}
+    }} &
+    running=$((running + 1))
+    if [ $running -ge {self.section_parallel} ]; then wait -n; running=$((running - 1)); fi
+'''.lstrip('\n').rstrip()
 
     def render(self):
+        if not self.items:
+            return self.render_header()
+        items = self.render_items()
+        eom = delimiter(items)
+        start, finish = ('running=0\n', '\nwait') if self.render_jobs() else ('', '')
         return f'''
 {self.render_header()}
 
-while read pkg; do
-{self.render_block()}
-done<<EOM
-{self.render_items()}
-EOM
+{start}while read pkg bin; do
+{self.render_guard()}{self.render_body()}
+done<<{eom}
+{items}
+{eom}{finish}
         '''.lstrip('\n').rstrip()
 
 class ContinuePackageType(PackageType):
-    def render_items(self):
-        return ' \\\n    '.join([render_item(item) for item in self.items])
+    def render_items(self, items=None):
+        items = self.items if items is None else items
+        return ' \\\n    '.join([render_arg(item) for item in items if item not in self.binaries])
+
+    def render_install(self):
+        items = [item for item in self.items if item not in self.binaries]
+        if self.check:
+            return '\n'.join([
+                f'{self.installed} {render_arg(item)} >/dev/null 2>&1 || {self.render_block()} {render_arg(item)}'
+                for item in items
+            ])
+        size = self.chunk_size or len(items) or 1
+        return '\n'.join([
+            f'{self.render_block()} {self.render_items(items[i:i + size])}'
+            for i in range(0, len(items), size)
+        ])
+
+    def render_guards(self):
+        return '\n'.join([
+            f'command -v {render_arg(binary)} >/dev/null || {self.render_block()} {render_arg(name)}'
+            for name, binary in self.binaries.items()
+        ])
 
     def render(self):
+        body = '\n'.join(filter(None, [
+            self.render_install(),
+            self.render_guards(),
+            self.render_maintenance(),
+        ]))
         return f'''
 {self.render_header()}
 
-{self.render_block()} {self.render_items()}
+{body}
         '''.lstrip('\n').rstrip()
 
 def path2str(func):
@@ -232,21 +608,49 @@ def divine_src(filepath, cwd):
 def divine_dst(filepath, srcpath, dstpath):
     return re.sub(srcpath, dstpath, filepath)
 @path2str
-def interpolate_home(filepath, home):
-    return re.sub('\$HOME', home, filepath)
+def interpolate_env(filepath, home):
+    def replace(match):
+        name = match.group(1) or match.group(2)
+        if not name:
+            return match.group(0)
+        if name == 'HOME':
+            return home or HOME
+        return os.environ.get(name, match.group(0))
+    return re.sub(r'\$\$|\$\{(\w+)\}|\$(\w+)', replace, filepath)
+@path2str
+def interpolate_winhome(filepath, winhome):
+    if '$WINHOME' in filepath and not winhome:
+        sys.exit(f'$WINHOME used in {filepath} but no windows home was found; pass --win-home')
+    return filepath.replace('$WINHOME', winhome or '')
 @path2str
 def interpolate_root(filepath, root='/'):
     return re.sub('ROOT', root, filepath)
 
 class Link(HeredocPackageType):
+    tools = ['realpath']
+
     def __init__(self, spec, patterns, cwd, root='/', home=None, **kwargs):
         self.cwd = cwd
         self.root = root
         self.home = home
         self.recursive = spec.pop('recursive', False)
-        if self.recursive:
+        self.skip_existing = kwargs.get('skip_existing_links') and not kwargs.get('force_links')
+        variables = kwargs.get('variables') or {}
+        winhome = kwargs.get('win_home')
+        self.copies = []
+        self.runtime = self.recursive and kwargs.get('link_expansion') == 'runtime'
+        if self.runtime:
+            self.items = [
+                (
+                    rehome(normalize(os.path.join(cwd, srcpath)), home),
+                    normalize(interpolate_env(interpolate_root(validate_path(interpolate_winhome(substitute(dstpath, variables), winhome)), root), home)),
+                )
+                for srcpath, dstpath in spec.items()
+            ]
+        elif self.recursive:
             self.items = []
             for srcpath, dstpath in spec.items():
+                srcpath = normalize(srcpath)
                 items = [
                     item.relative_to(self.cwd)
                     for item in Path(os.path.join(self.cwd, srcpath)).rglob('*')
@@ -254,37 +658,126 @@ class Link(HeredocPackageType):
                 ]
                 for item in items:
                     src = divine_src(item, cwd)
-                    dst = divine_dst(item, srcpath, dstpath)
+                    dst = divine_dst(item, srcpath, interpolate_winhome(substitute(dstpath, variables), winhome))
                     dst = interpolate_root(dst, root)
-                    dst = interpolate_home(dst, home)
-                    self.items += [(src, dst)]
+                    dst = interpolate_env(dst, home)
+                    self.items += [(rehome(normalize(src), home), normalize(dst))]
         else:
-            self.items = [(os.path.join(cwd, src), interpolate_home(dst, home)) for src, dst in spec.items()]
+            self.items = []
+            for src, dst in spec.items():
+                owner = ()
+                items = self.items
+                if isinstance(dst, dict) and 'base' in dst:
+                    pairs = [(source, os.path.join(dst['base'], os.path.basename(source))) for source in dst['sources']]
+                elif isinstance(dst, dict):
+                    owner = (dst['owner'],) if dst.get('owner') else ()
+                    items = self.copies if dst.get('copy') else self.items
+                    pairs = [(src, dst['dst'])]
+                else:
+                    pairs = [(src, dst)]
+                items += [
+                    (rehome(normalize(os.path.join(cwd, src)), home), normalize(interpolate_env(validate_path(interpolate_winhome(substitute(dst, variables), winhome)), home))) + owner
+                    for src, dst in pairs
+                ]
 
     def __repr__(self):
         return f'{type(self).__name__}(recursive={self.recursive}, items={self.items})'
 
     __str__ = __repr__
 
+    def names(self):
+        return [render_item(item) for item in self.items + self.copies]
+
     def functions(self):
         return LINKER
 
+    def render_guard(self):
+        if not self.skip_existing:
+            return ''
+        return '''
+    [ -e "${link/#\~/$HOME}" ] && [ ! -L "${link/#\~/$HOME}" ] && { echo "skip (real file): $link"; continue; }
+'''.lstrip('\n')
+
+    def render_chown(self, items):
+        if not any(len(item) > 2 for item in items):
+            return ''
+        return '''
+    if [ -n "$owner" ]; then sudo chown -h "$owner" "${link/#\~/$HOME}"; fi'''
+
+    def render_tree(self, src, dst):
+        return f'''
+find {quote_path(src)} -type f | while read -r file; do
+    link={quote_path(dst)}/"${{file#{quote_path(src)}/}}"
+{self.render_guard()}    linker "$file" "$link"
+done
+'''.lstrip('\n').rstrip()
+
     def render(self):
+        if self.runtime:
+            return 'echo "links:"\n' + '\n'.join([self.render_tree(src, dst) for src, dst in self.items])
+        items = self.render_items()
+        eom = delimiter(items)
         return f'''
 echo "links:"
-while read -r file link; do
-    linker $file $link
-done<<EOM
-{self.render_items()}
-EOM
-        '''.lstrip('\n').rstrip()
+while read -r file link owner; do
+{self.render_guard()}    linker "$file" "$link"{self.render_chown(self.items)}
+done<<{eom}
+{items}
+{eom}
+        '''.lstrip('\n').rstrip() + self.render_copies()
+
+    def render_copies(self):
+        if not self.copies:
+            return ''
+        items = '\n'.join([render_item(item) for item in self.copies])
+        eom = delimiter(items)
+        return f'''
+while read -r file link owner; do
+    echo "[copy] $link <- $file"
+    [ -L "${{link/#\~/$HOME}}" ] && unlink "${{link/#\~/$HOME}}"
+    mkdir -p "$(dirname "${{link/#\~/$HOME}}")"; cp -f "$file" "${{link/#\~/$HOME}}"{self.render_chown(self.copies)}
+done<<{eom}
+{items}
+{eom}
+'''.rstrip()
 
 class APT(ContinuePackageType):
+    pkgmgr = 'deb'
+    tools = ['apt']
+    installed = 'dpkg -s'
+    chunked = True
+    maintenance = 'sudo apt autoremove -y && sudo apt clean'
+
+    def __init__(self, spec, patterns, **kwargs):
+        super(APT, self).__init__(spec, patterns, **kwargs)
+        self.sources = spec.get('sources') or {}
+        for name in self.sources:
+            if not SOURCE_PATTERN.match(str(name)) or name in ('.', '..'):
+                sys.exit(f'invalid apt source name: {name!r}')
+
+    def render_source(self, name, source):
+        keyring = f'/etc/apt/keyrings/{name}.gpg'
+        suite = render_arg(source['suite']) if 'suite' in source else '"$(lsb_release -cs)"'
+        components = source.get('components', 'main')
+        if isinstance(components, list):
+            components = ' '.join(components)
+        return f'''
+curl -fsSL {render_arg(source['keyring_url'])} | gpg --dearmor | sudo tee {keyring} >/dev/null
+echo "deb [signed-by={keyring}]" {render_arg(source['url'])} {suite} {render_arg(components)} | sudo tee /etc/apt/sources.list.d/{name}.list >/dev/null
+'''.lstrip('\n').rstrip()
+
+    def render_sources(self):
+        if not self.sources:
+            return ''
+        return 'sudo mkdir -p /etc/apt/keyrings\n' + '\n'.join([
+            self.render_source(name, source) for name, source in self.sources.items()
+        ]) + '\n\n'
+
     def render_header(self):
         return f'''
 {PackageType.render_header(self)}
 
-sudo apt update && sudo apt upgrade -y && sudo apt install -y software-properties-common
+{self.render_sources()}sudo apt update && sudo apt upgrade -y && sudo apt install -y software-properties-common
         '''.lstrip('\n').rstrip()
 
     def render_block(self):
@@ -293,12 +786,74 @@ sudo apt install -y
         '''.lstrip('\n').rstrip()
 
 class DNF(ContinuePackageType):
+    pkgmgr = 'rpm'
+    tools = ['dnf']
+    installed = 'rpm -q'
+    chunked = True
+    maintenance = 'sudo dnf autoremove -y && sudo dnf clean all'
+
+    def render_block(self):
+        return '''
+sudo dnf install -y
+        '''.lstrip('\n').rstrip()
+
+class BREW(ContinuePackageType):
+    pkgmgr = 'brew'
+    tools = ['brew']
+    installed = 'brew list'
+    maintenance = 'brew autoremove && brew cleanup'
+
+    def render_block(self):
+        return '''
+brew install
+        '''.lstrip('\n').rstrip()
+
+class PACMAN(ContinuePackageType):
+    pkgmgr = 'arch'
+    tools = ['pacman']
+    installed = 'pacman -Qi'
+
+    def __init__(self, spec, patterns, **kwargs):
+        super(PACMAN, self).__init__(spec, patterns, **kwargs)
+        self.aur = [validate_pkg('pacman', item) for item in spec.get('aur', [])]
+
+    def names(self):
+        return ManifestType.names(self) + self.aur
+
+    def requirements(self):
+        return self.tools + (['yay'] if self.aur else [])
+
     def render_block(self):
         return '''
-    sudo dnf install -y $pkg
+sudo pacman -S --noconfirm
         '''.lstrip('\n').rstrip()
 
+    def render(self):
+        result = ContinuePackageType.render(self)
+        if not self.aur:
+            return result
+        return f'{result}\nyay -S --noconfirm ' + ' \\\n    '.join(map(render_arg, self.aur))
+
 class PPA(HeredocPackageType):
+    tools = ['add-apt-repository']
+
+    def __init__(self, spec, patterns, **kwargs):
+        super(PPA, self).__init__(spec, patterns, **kwargs)
+        self.remove = [validate_pkg('ppa', item) for item in spec.get('remove', [])]
+
+    def names(self):
+        return ManifestType.names(self) + self.remove
+
+    def render_header(self):
+        if not self.remove:
+            return PackageType.render_header(self)
+        removes = '\n'.join([f'sudo add-apt-repository --remove -y "ppa:{ppa}"' for ppa in self.remove])
+        return f'''
+{PackageType.render_header(self)}
+
+{removes}
+'''.lstrip('\n').rstrip()
+
     def render_block(self):
         if not self.items:
             return ''
@@ -310,34 +865,78 @@ class PPA(HeredocPackageType):
 '''.lstrip('\n').rstrip()
 
 class NPM(ContinuePackageType):
+    tools = ['npm']
+    installed = 'npm ls -g'
+
+    def __init__(self, spec, patterns, cwd=None, **kwargs):
+        super(NPM, self).__init__(spec, patterns, **kwargs)
+        source = spec.get('from')
+        self.source = normalize(os.path.join(cwd or '', os.path.expanduser(validate_path(source)))) if source else None
+
     def render_block(self):
         return f'''
 sudo npm install -g
 '''.lstrip('\n').rstrip()
 
+    def render(self):
+        result = ContinuePackageType.render(self)
+        if not self.source:
+            return result
+        return f'{result}\n(cd {quote_path(self.source)} && npm ci)'
+
 class PIP3(ContinuePackageType):
+    tools = ['pip3']
+    installed = 'pip3 show'
+
+    def __init__(self, spec, patterns, **kwargs):
+        super(PIP3, self).__init__(spec, patterns, **kwargs)
+        self.index_url = spec.get('index_url')
+        self.extra_index_url = spec.get('extra_index_url', [])
+
     def render_header(self):
         return f'''
 {PackageType.render_header(self)}
 
 sudo apt-get install -y python3-dev
-sudo -H pip3 install --upgrade pip setuptools
+{self.render_block()} pip setuptools
 '''.lstrip('\n').rstrip()
 
     def render_block(self):
+        flags = f' --index-url {render_arg(self.index_url)}' if self.index_url else ''
+        flags += ''.join([f' --extra-index-url {render_arg(url)}' for url in self.extra_index_url])
         return f'''
-sudo -H pip3 install --upgrade
+sudo -H pip3 install --upgrade{flags}
 '''.lstrip('\n').rstrip()
 
 class PIPX(HeredocPackageType):
+    tools = ['pipx']
+    parallel = True
+
     def render_block(self):
         if not self.items:
             return ''
         return '''
-    pipx install $pkg
+    pipx install "$pkg"
 '''.lstrip('\n').rstrip()
 
 class FLATPAK(ContinuePackageType):
+    tools = ['flatpak']
+    installed = 'flatpak info'
+
+    def __init__(self, spec, patterns, **kwargs):
+        super(FLATPAK, self).__init__(spec, patterns, **kwargs)
+        self.remote = validate_pkg('flatpak', spec.get('remote', 'flathub'))
+        self.runtimes = [validate_pkg('flatpak', runtime) for runtime in spec.get('runtimes', [])]
+
+    def render_header(self):
+        if not self.runtimes:
+            return PackageType.render_header(self)
+        return f'''
+{PackageType.render_header(self)}
+
+{self.render_block()} {render_arg(self.remote)} {' '.join(map(render_arg, self.runtimes))}
+'''.lstrip('\n').rstrip()
+
     def render_block(self):
         return f'''
 flatpak install --assumeyes --or-update
@@ -346,42 +945,156 @@ flatpak install --assumeyes --or-update
 class Repo():
     def __init__(self, baseurl, reponame, spec, cwd, repopath, **kwargs):
         self.baseurl = baseurl
-        self.reponame = reponame
+        self.reponame = normalize(reponame)
         self.repopath = repopath
         self.cwd = cwd
-        cwd = os.path.join(self.cwd, repopath, reponame)
+        self.enabled_if = spec.get('enabled_if')
+        self.inherit = spec.get('inherit', False)
+        self.token_env = spec.get('token_env')
+        if self.token_env and not ENV_PATTERN.match(str(self.token_env)):
+            sys.exit(f'invalid token_env for {reponame}: {self.token_env!r}')
+        clone_opts = spec.get('clone_opts') or {}
+        self.submodules = clone_opts.get('submodules', True)
+        self.lfs = clone_opts.get('lfs', True)
+        self.depth = clone_opts.get('depth')
+        if self.depth is not None and (type(self.depth) is not int or self.depth < 1):
+            sys.exit(f'invalid depth for {reponame}: {self.depth!r}; expected a positive integer')
+        self.sparse = [validate_path(path) for path in spec.get('sparse') or []]
+        self.group = spec.get('group')
+        self.env = spec.get('env') or {}
+        for name in self.env:
+            if not ENV_PATTERN.match(str(name)):
+                sys.exit(f'invalid env name for {reponame}: {name!r}')
+        self.transport = spec.get('transport', 'git')
+        if self.transport not in ('git', 'gh'):
+            sys.exit(f'invalid transport for {reponame}: {self.transport!r}; expected git or gh')
+        self.commit = spec.get('commit')
+        if self.commit and not COMMIT_PATTERN.match(str(self.commit)):
+            sys.exit(f'invalid commit for {reponame}: {self.commit!r}; expected a full 40-character sha')
+        self.home = kwargs.get('home')
+        self.fullpath = normalize(os.path.join(self.cwd, repopath, reponame))
         self.link = Link(
             spec.get('link'),
             None,
-            cwd=os.path.join(self.cwd, 'repos', self.reponame),
+            cwd=self.fullpath,
             **kwargs) if 'link' in spec else None
         self.script = Script(
-            dict(reponame=spec.get('script')),
-            None,
+            {self.reponame: spec.get('script')},
+            ['*'],
             **kwargs) if 'script' in spec else None
+        self.post_clone = Script(
+            {f'{self.reponame}.post_clone': spec.get('post_clone')},
+            ['*'],
+            **kwargs) if 'post_clone' in spec else None
 
     __repr__ = __repr__
 
     __str__ = __repr__
 
+    def render_env(self):
+        return '' if self.lfs else 'GIT_LFS_SKIP_SMUDGE=1 '
+
+    def render_clone(self, fullpath):
+        flags = ' --recursive' if self.submodules else ''
+        if self.depth:
+            flags += f' --depth {self.depth}'
+        if self.sparse:
+            flags += ' --filter=blob:none --sparse'
+        if self.transport == 'gh':
+            flags = f' --{flags}' if flags else ''
+            return f'{self.render_env()}gh repo clone {self.reponame} {quote_path(fullpath)}{flags}'
+        return f'{self.render_env()}git {self.render_auth()}clone{flags} {self.render_url()} {quote_path(fullpath)}'
+
+    def render_transport_check(self):
+        if self.transport != 'gh':
+            return ''
+        return f'''
+command -v gh >/dev/null || {{ echo "gh is required to clone {self.reponame}"; exit 1; }}
+'''.lstrip('\n')
+
+    def render_url(self):
+        return f'{self.baseurl}/{self.reponame}'
+
+    def render_auth(self):
+        # the token is read by the helper at runtime so it never lands in
+        # .git/config, the process list or set -x output
+        if not self.token_env:
+            return ''
+        return f'''-c credential.helper='!f() {{ echo username=x-access-token; echo "password=${self.token_env}"; }}; f' '''
+
+    def render_exports(self):
+        return ''.join([f'export {name}={shlex.quote(str(value))}\n' for name, value in self.env.items()])
+
+    def render_token_check(self):
+        if not self.token_env:
+            return ''
+        return f'''
+if [ -z "${{{self.token_env}}}" ]; then
+    echo "{self.token_env} must be set to clone {self.reponame}"
+    exit 1
+fi
+'''.lstrip('\n')
+
+    def render_sparse(self, fullpath):
+        if not self.sparse:
+            return ''
+        return f'''
+git -C {quote_path(fullpath)} {self.render_auth()}sparse-checkout set {' '.join(map(quote_path, self.sparse))}
+'''.lstrip('\n')
+
+    def render_update(self, fullpath):
+        if not self.commit:
+            return f'(cd {quote_path(fullpath)} && pwd && {self.render_env()}git {self.render_auth()}pull && git checkout HEAD)'
+        return f'''
+(cd {quote_path(fullpath)} && pwd && {self.render_env()}git {self.render_auth()}fetch && git {self.render_auth()}checkout {self.commit})
+[ "$(git -C {quote_path(fullpath)} rev-parse HEAD)" = "{self.commit}" ] || {{ echo "commit mismatch: {self.reponame}"; exit 1; }}
+'''.strip('\n')
+
+    def render_inherit(self, fullpath):
+        if not self.inherit:
+            return ''
+        return f'''
+if [ -f {quote_path(fullpath)}/.manifest.yml ]; then
+    (set -o pipefail; manifest -C {quote_path(fullpath)}/.manifest.yml -R '' | bash)
+fi
+'''.lstrip('\n')
+
     def render(self):
         link = self.link.render() + '\n' if self.link else ''
-        script = self.script.render() + '\n' if self.script else ''
-        fullpath = os.path.join(self.cwd, self.repopath, self.reponame)
-        return f'''
+        script = self.script.render_scripts() + '\n' if self.script else ''
+        fullpath = rehome(self.fullpath, self.home)
+        post_clone = f'(cd {quote_path(fullpath)}\n{self.post_clone.render_scripts()})\n' if self.post_clone else ''
+        result = f'''
 echo "{self.reponame}:"
-git clone --recursive {self.baseurl}/{self.reponame} {fullpath}
-(cd {fullpath} && pwd && git pull && git checkout HEAD)
-{link}
+(
+set -e
+{self.render_exports()}{self.render_token_check()}{self.render_transport_check()}mkdir -p {quote_path(os.path.dirname(fullpath))}
+[ -d {quote_path(fullpath)}/.git ] || {self.render_clone(fullpath)}
+{self.render_sparse(fullpath)}{self.render_update(fullpath)}
+{post_clone}{self.render_inherit(fullpath)}{link}
 {script}
+true
+)
+if [ $? -ne 0 ]; then echo "[failed] {self.reponame}"; fi
+'''.lstrip('\n').rstrip()
+        if self.enabled_if:
+            result = f'''
+if {self.enabled_if}; then
+{result}
+else
+    echo "[skip] {self.reponame}"
+fi
 '''.lstrip('\n').rstrip()
+        return result
 
 class Github(ManifestType):
-    def __init__(self, spec, cwd, patterns, **kwargs):
-        repopath = spec.pop('repopath', 'repos')
+    tools = ['git']
+
+    def __init__(self, spec, cwd, patterns, exclude=None, **kwargs):
+        repopath = validate_path(spec.pop('repopath', 'repos'))
         self.repos = [
             Repo('https://github.com', reponame, repobody, cwd, repopath, **kwargs)
-            for reponame, repobody in fuzzy(spec).include(*patterns).items()
+            for reponame, repobody in select(spec, patterns, exclude).items()
         ]
 
     def __repr__(self):
@@ -389,17 +1102,33 @@ class Github(ManifestType):
 
     __str__ = __repr__
 
+    def names(self):
+        return [repo.reponame for repo in self.repos]
+
+    def requirements(self):
+        extra = ['gh'] if any(repo.transport == 'gh' for repo in self.repos) else []
+        return self.tools + extra
+
     def functions(self):
         return LINKER
 
     def render(self):
         if not self.repos:
             return ''
-        return 'echo "github repos:"\n\n' + '\n\n'.join([repo.render() for repo in self.repos]).strip()
+        groups = {}
+        for repo in self.repos:
+            groups.setdefault(repo.group, []).append(repo)
+        blocks = []
+        for group, repos in groups.items():
+            header = f'echo "github repos [{group}]:"' if group else 'echo "github repos:"'
+            require = '' if blocks else REQUIRE_GIT + '\n\n'
+            blocks += [header + '\n\n' + require + '\n\n'.join([repo.render() for repo in repos]).strip()]
+        return '\n\n'.join(blocks)
 
 class Script(ManifestType):
-    def __init__(self, spec, patterns, **kwargs):
-        self.items = fuzzy(spec).include(*patterns)
+    def __init__(self, spec, patterns, variables=None, exclude=None, **kwargs):
+        self.items = select(spec, patterns, exclude)
+        self.variables = variables or {}
 
     def __repr__(self):
         return f'{type(self).__name__}(items={self.items})'
@@ -409,10 +1138,126 @@ class Script(ManifestType):
     def functions(self):
         return LATEST
 
+    def render_write(self, write):
+        if not write:
+            return ''
+        path = validate_path(substitute(write['path'], self.variables))
+        content = write.get('content', '').rstrip('\n')
+        eom = delimiter(content, 'EOF')
+        return f'''
+mkdir -p "$(dirname "{path}")"
+cat > "{path}" <<'{eom}'
+{content}
+{eom}
+'''.lstrip('\n')
+
+    def render_script(self, name, script):
+        isolated = True
+        if isinstance(script, dict):
+            isolated = script.get('isolated', True)
+            script = self.render_write(script.get('write')) + (script.get('run') or '')
+        body = substitute(script or '', self.variables).rstrip() or ':'
+        begin, end = ('(', ')') if isolated else ('{', '}')
+        log = f'{SCRIPT_LOGS}/{name.replace("/", "_")}.log'
+        return f'''
+echo "{name}:"
+{begin}
+{body}
+{end} > >(tee "{log}") 2>&1
+'''.lstrip('\n')
+
+    def render_scripts(self):
+        return f'mkdir -p {SCRIPT_LOGS}\n\n' + '\n\n'.join([
+            self.render_script(name, script) for name, script in self.items.items()
+        ])
+
     def render(self):
         if not self.items:
             return ''
-        return 'echo "scripts:"\n\n' +  '\n\n'.join([f"echo \"{name}:\"\n{script}\n" for name, script in self.items.items()])
+        return f'echo "scripts:"\n\n{self.render_scripts()}'
+
+def merge_pkg(spec, name):
+    aliases = spec.get('aliases') or {}
+    pkgs = [
+        ((aliases.get(pkg) if isinstance(pkg, str) else None) or {}).get(name, pkg)
+        for pkg in (spec.get('pkg') or {}).get('items', [])
+    ]
+    section = spec.get(name) or {}
+    return dict(section, items=pkgs + section.get('items', []))
+
+SECTIONS = dict(
+    link=Link,
+    ppa=PPA,
+    apt=APT,
+    dnf=DNF,
+    brew=BREW,
+    pacman=PACMAN,
+    npm=NPM,
+    pip3=PIP3,
+    pipx=PIPX,
+    flatpak=FLATPAK,
+    github=Github,
+    script=Script,
+)
+
+def platform_facts():
+    distro = ''
+    try:
+        with open('/etc/os-release') as f:
+            fields = dict(line.strip().split('=', 1) for line in f if '=' in line)
+        distro = fields.get('ID', '').strip('"')
+    except IOError:
+        pass
+    return dict(os=platform.system().lower(), arch=platform.machine(), distro=distro)
+
+def evaluate_when(when, facts):
+    # when: 'os==linux && arch==x86_64 || distro==fedora'; && binds tighter than ||
+    # a list of predicates must all hold
+    if isinstance(when, list):
+        return all(evaluate_when(predicate, facts) for predicate in when)
+    def check(predicate):
+        match = re.match(r'^\s*(\w+)\s*(==|!=)\s*([\w.-]+)\s*$', predicate)
+        if not match or match.group(1) not in facts:
+            sys.exit(f'invalid when predicate: {predicate!r}; expected (os|arch|distro)(==|!=)value')
+        key, op, value = match.groups()
+        return (facts[key] == value) == (op == '==')
+    return any(
+        all(check(predicate) for predicate in clause.split('&&'))
+        for clause in str(when).split('||')
+    )
+
+def managers():
+    return [name for name, cls in SECTIONS.items() if issubclass(cls, PackageType) and cls is not Link]
+
+def parse_managers(value):
+    names = [name.strip() for name in value.split(',') if name.strip()]
+    unknown = [name for name in names if name not in managers()]
+    if unknown:
+        raise ArgumentTypeError(f'unknown managers: {", ".join(unknown)}; choose from {", ".join(managers())}')
+    return names
+
+def positive_int(value):
+    try:
+        number = int(value)
+    except ValueError:
+        raise ArgumentTypeError(f'invalid int value: {value!r}')
+    if number < 1:
+        raise ArgumentTypeError(f'must be at least 1: {number}')
+    return number
+
+def check_pkgmgr(spec, active, pkgmgr, strict=False):
+    others = [
+        name for name, cls in SECTIONS.items()
+        if cls.pkgmgr and name != active and (spec.get(name) or {}).get('items')
+    ]
+    if others:
+        message = f'pkgmgr={pkgmgr} has no {active} items but {", ".join(others)} is populated; wrong platform or mislabeled section?'
+        if strict:
+            sys.exit(message)
+        warn(message)
+
+def section_name(section):
+    return next(name for name, cls in SECTIONS.items() if type(section) is cls)
 
 class Manifest():
     def __init__(
@@ -421,62 +1266,125 @@ class Manifest():
             cwd=None,
             complete=True,
             pkgmgr=None,
-            link=None,
-            ppa=None,
-            apt=None,
-            dnf=None,
-            npm=None,
-            pip3=None,
-            pipx=None,
-            flatpak=None,
-            github=None,
-            script=None,
+            config=None,
+            emit_metadata_header=False,
+            strict=False,
+            timeout_per_section=None,
+            no_banner=False,
+            banner=None,
+            only_managers=None,
             **kwargs):
         self.verbose = spec.pop('verbose', False)
         self.errors = spec.pop('errors', False)
+        kwargs['variables'] = spec.pop('vars', None) or {}
+        self.config = config
+        self.emit_metadata_header = emit_metadata_header
+        self.timeout_per_section = timeout_per_section
+        self.no_banner = no_banner
+        self.banner = banner
+        self.pkgmgr = pkgmgr
         self.sections = []
-        if complete or link != None:
-            self.sections += [Link(spec['link'], link, cwd, **kwargs)]
-        if complete or ppa != None:
-            self.sections += [PPA(spec['ppa'], ppa, **kwargs)]
-        pkgs = spec.get('pkg', {}).get('items', [])
-        apts = pkgs + spec.get('apt', {}).get('items', []) if complete or apt != None else []
-        dnfs = pkgs + spec.get('dnf', {}).get('items', []) if complete or dnf != None else []
-        if pkgmgr == 'deb' and apts:
-            self.sections += [APT(dict(items=apts), apt, **kwargs)]
-        elif pkgmgr == 'rpm' and dnfs:
-            self.sections += [DNF(dict(items=dnfs), dnf, **kwargs)]
-        if complete or npm != None:
-            self.sections += [NPM(spec['npm'], npm, **kwargs)]
-        if complete or pip3 != None:
-            self.sections += [PIP3(spec['pip3'], pip3, **kwargs)]
-        if complete or pipx != None:
-            self.sections += [PIPX(spec['pipx'], pipx, **kwargs)]
-        if complete or flatpak != None:
-            self.sections += [FLATPAK(spec['flatpak'], flatpak, **kwargs)]
-        if complete or github != None:
-            self.sections += [Github(spec['github'], cwd, github, **kwargs)]
-        if complete or script != None:
-            self.sections += [Script(spec['script'], script, **kwargs)]
+        self.effective = {}
+        self.facts = platform_facts()
+        patterns = {name: kwargs.pop(name, None) for name in SECTIONS}
+        for name, cls in SECTIONS.items():
+            if cls.pkgmgr and cls.pkgmgr == pkgmgr:
+                self.effective[name] = list(dict.fromkeys([split_binary(item)[0] for item in merge_pkg(spec, name)['items']]))
+            if only_managers and name in managers() and name not in only_managers:
+                continue
+            if patterns[name] is None:
+                if not complete:
+                    continue
+                patterns[name] = ['*']
+            section = spec.get(name) or {}
+            when = section.pop('when', None) if 'when' in (FIELDS[name] or []) and isinstance(section, dict) else None
+            if when and not evaluate_when(when, self.facts):
+                continue
+            if cls.pkgmgr:
+                if cls.pkgmgr != pkgmgr:
+                    continue
+                section = merge_pkg(spec, name)
+                if not section['items'] and not section.get('aur'):
+                    check_pkgmgr(spec, name, pkgmgr, strict)
+                    continue
+            self.sections += [cls(section, patterns=patterns[name], cwd=cwd, **kwargs)]
+        order = spec.get('order') or []
+        rank = lambda name: order.index(name) if name in order else len(order)
+        self.sections.sort(key=lambda section: rank(section_name(section)))
+        self.check_requires()
+        self.check_timeout()
+
+    def check_timeout(self):
+        if not self.timeout_per_section:
+            return
+        for section in self.sections:
+            if type(section) is not Script:
+                continue
+            for name, script in section.items.items():
+                if isinstance(script, dict) and not script.get('isolated', True):
+                    sys.exit(f'script {name} sets isolated: False, which cannot affect later sections under --timeout-per-section')
+
+    def check_requires(self):
+        names = [section_name(section) for section in self.sections]
+        for section in self.sections:
+            if type(section) is not Script:
+                continue
+            for name, script in section.items.items():
+                requires = script.get('requires', []) if isinstance(script, dict) else []
+                for required in requires:
+                    if required not in names:
+                        warn(f'script {name} requires section {required}, which is not present')
+                    elif names.index(required) > names.index('script'):
+                        warn(f'script {name} requires section {required}, which is emitted after it')
 
     def __repr__(self):
         return f'{type(self).__name__}(verbose={self.verbose}, errors={self.errors}, sections={self.sections})'
 
-    def render_header(self):
+    def render_effective(self):
+        if not self.effective:
+            return f'no package section for pkgmgr={self.pkgmgr}'
+        return '\n'.join([
+            f'{name}:\n  items:\n' + '\n'.join([f'    - {item}' for item in items])
+            for name, items in self.effective.items()
+        ])
+
+    def render_metadata(self):
+        if not self.emit_metadata_header:
+            return ''
+        return f'''
+# manifest: {self.config}
+# version: {git_describe()}
+# hostname: {socket.gethostname()}
+# generated: {datetime.now().isoformat(timespec='seconds')}
+'''.lstrip('\n')
+
+    def render_comments(self):
+        if self.no_banner:
+            return self.render_metadata()
         return f'''
-#!/bin/bash
 # generated file by manifest.py
 # src: https://github.com/scottidler/.../blob/master/manifest.py
+{self.render_metadata()}'''.lstrip('\n')
 
+    def render_banner(self):
+        if not self.banner:
+            return ''
+        return f'echo {shlex.quote(self.banner)}\n\n'
+
+    def render_header(self):
+        return f'''
+#!/bin/bash
+{self.render_comments()}
 {DEBUG}
 
-'''.lstrip('\n')
+{self.render_banner()}'''.lstrip('\n')
 
 
     def render_functions(self, sep='\n\n', prefix=None, suffix='\n\n'):
         if not self.sections:
             return ''
-        result = sep.join(set([section.functions() for section in self.sections])).lstrip('\n').rstrip()
+        functions = dict.fromkeys([section.functions() for section in self.sections])
+        result = sep.join(filter(None, functions)).lstrip('\n').rstrip()
         if result:
             if prefix:
                 result = prefix + result
@@ -484,10 +1392,25 @@ class Manifest():
                 result += suffix
         return result
 
+    def render_watchdog(self, section):
+        body = section.render()
+        if not self.timeout_per_section or not body:
+            return body
+        script = '\n\n'.join(filter(None, [self.render_functions().rstrip(), body]))
+        eom = delimiter(script, 'SECTION')
+        guard = REQUIRE_GIT + '\n' if type(section) is Github else ''
+        return f'''
+{guard}timeout {self.timeout_per_section} bash -c "$(cat <<'{eom}'
+{script}
+{eom}
+)"
+if [ $? -eq 124 ]; then echo "[timeout] {section_name(section)} exceeded {self.timeout_per_section}s"; fi
+'''.lstrip('\n').rstrip()
+
     def render_body(self, sep='\n\n', prefix=None, suffix='\n\n'):
         if not self.sections:
             return ''
-        result = sep.join([section.render() for section in self.sections]).lstrip('\n').rstrip()
+        result = sep.join([self.render_watchdog(section) for section in self.sections]).lstrip('\n').rstrip()
         if result:
             if prefix:
                 result = prefix + result
@@ -506,11 +1429,136 @@ class Manifest():
             self.render_footer(),
         ])
 
+    def resolve_links(self):
+        links = []
+        for section in self.sections:
+            if type(section) is Link:
+                links += [section]
+            elif type(section) is Github:
+                links += [repo.link for repo in section.repos if repo.link]
+        return [
+            dict(source=item[0], dest=re.sub(r'^~(?=/|$)', lambda _: link.home or HOME, item[1]), copy=item in link.copies)
+            for link in links for item in link.items + link.copies
+        ]
+
+    def render_dry_run(self, verbose=False):
+        blocks = [
+            f'{section_name(section)} ({len(section.names())}):' + ''.join([f'\n  {name}' for name in sorted(section.names())])
+            for section in self.sections
+        ]
+        if verbose:
+            links = self.resolve_links()
+            blocks += [f'resolved links ({len(links)}):' + ''.join([
+                f'\n  {link["source"]} -> {link["dest"]}' + (' (copy)' if link['copy'] else '')
+                for link in links
+            ])]
+        return '\n\n'.join(blocks)
+
+    def render_doctor(self):
+        rows = [('SECTION', 'TOOL', 'STATUS')]
+        rows += [('pkgmgr', self.pkgmgr, 'detected' if self.pkgmgr in dict(PKGMGRS).values() else 'unknown')]
+        for section in self.sections:
+            if not section.names():
+                continue
+            rows += [
+                (section_name(section), tool, 'present' if check_hash(tool) else 'missing')
+                for tool in dict.fromkeys(section.requirements())
+            ]
+        return tabulate(rows)
+
+    def summarize(self):
+        return [
+            dict(
+                section=section_name(section),
+                type=type(section).__name__,
+                count=len(section.names()),
+                functions=bool(section.functions()))
+            for section in self.sections
+        ]
+
+    def build(self):
+        return dict(script=self.render(), sections=self.summarize())
+
+    def render_plan(self, samples=3):
+        rows = [('SECTION', 'TYPE', 'COUNT', 'SAMPLE')]
+        for section in self.sections:
+            names = section.names()
+            sample = ', '.join(names[:samples]) + (', ...' if len(names) > samples else '')
+            rows += [(section_name(section), type(section).__name__, len(names), sample)]
+        return tabulate(rows)
+
+    def render_section(self, name):
+        sections = [section for section in self.sections if type(section) is SECTIONS[name]]
+        return '\n\n'.join(filter(None, [
+            *[section.functions() for section in sections],
+            *[section.render() for section in sections],
+        ]))
+
     __str__ = __repr__
 
-def load_manifest(complete=True, config=None, reponame=None, **kwargs):
+def unknown_fields(spec):
+    unknown = [key for key in spec if key not in FIELDS]
+    for key, fields in FIELDS.items():
+        if fields and isinstance(spec.get(key), dict):
+            unknown += [f'{key}.{field}' for field in spec[key] if field not in fields]
+    for reponame, repobody in (spec.get('github') or {}).items():
+        if isinstance(repobody, dict):
+            unknown += [f'github.{reponame}.{field}' for field in repobody if field not in REPO_FIELDS]
+    return unknown
+
+def read_yaml(path):
+    with open(path, encoding='utf-8-sig') as f:
+        return yaml.safe_load(f.read().replace('\r\n', '\n'))
+
+def merge_specs(base, local):
+    merged = dict(base)
+    for key, value in local.items():
+        if isinstance(value, list) and isinstance(merged.get(key), list):
+            merged[key] = merged[key] + [item for item in value if item not in merged[key]]
+        elif isinstance(value, dict) and isinstance(merged.get(key), dict):
+            merged[key] = merge_specs(merged[key], value)
+        else:
+            merged[key] = value
+    return merged
+
+def read_manifest(path, visited=()):
+    path = os.path.abspath(path)
+    if path in visited:
+        sys.exit('include cycle: ' + ' -> '.join(visited + (path,)))
+    spec = read_yaml(path) or {}
+    sources = []
+    for include in spec.pop('include', None) or []:
+        include = os.path.join(os.path.dirname(path), os.path.expanduser(include))
+        included, included_sources = read_manifest(include, visited + (path,))
+        spec = merge_specs(included, spec)
+        sources += included_sources
+    return spec, sources + [path]
+
+def find_config(config=None):
+    if config:
+        if not os.path.exists(config):
+            sys.exit(f'config not found: {config}')
+        return config
+    xdg = os.environ.get('XDG_CONFIG_HOME')
+    paths = [
+        os.path.join(xdg, 'manifest', 'manifest.yml') if xdg else None,
+        expand('~/.config/manifest/manifest.yml'),
+        os.path.abspath('manifest.yml'),
+        f'{REAL_PATH}/manifest.yml',
+    ]
+    paths = [path for path in paths if path]
+    for path in paths:
+        if os.path.exists(path):
+            return path
+    sys.exit('no manifest found; tried:\n  ' + '\n  '.join(paths))
+
+def load_spec(config, reponame, base=None):
     repopath = expand('~/.config/manifest/')
-    spec = yaml.safe_load(open(config))
+    if os.path.isdir(config):
+        sys.exit(f'{config} is a directory; -C/--config expects a manifest file (e.g. {os.path.join(config, "manifest.yml")})')
+    spec, sources = read_manifest(config)
+    if not reponame:
+        return merge_base(spec, sources, base)
     with cd(repopath, mkdir=True):
         if not os.path.isdir(reponame):
             giturl = f'ssh://git@github.com/{reponame}'
@@ -519,14 +1567,93 @@ def load_manifest(complete=True, config=None, reponame=None, **kwargs):
             call('git clean -xfd')
             call('git reset --hard HEAD')
             call('git pull')
-            spec1 = yaml.safe_load(open(f'./manifest.yml'))
+            spec1, sources1 = read_manifest('./manifest.yml')
             spec.update(spec1)
-
-    manifest = Manifest(spec=spec, cwd=f'{repopath}/{reponame}', complete=complete, **kwargs)
+            sources += sources1
+    return merge_base(spec, sources, base)
+
+def merge_base(spec, sources, base=None):
+    if not base:
+        return spec, sources
+    merged, base_sources = read_manifest(base)
+    merged.update(spec)
+    return merged, base_sources + sources
+
+def merge_preview(config, reponame, base=None):
+    spec, sources = load_spec(config, reponame, base)
+    header = '\n'.join([f'# merged from: {source}' for source in sources])
+    return header + '\n' + yaml.safe_dump(spec, default_flow_style=False).rstrip()
+
+def load_manifest(complete=True, config=None, reponame=None, strict_yaml=False, base=None, **kwargs):
+    repopath = expand('~/.config/manifest/')
+    spec, _ = load_spec(config, reponame, base)
+    if strict_yaml:
+        unknown = unknown_fields(spec)
+        if unknown:
+            sys.exit('unknown fields in manifest:\n  ' + '\n  '.join(unknown))
+    cwd = f'{repopath}/{reponame}' if reponame else os.path.dirname(os.path.abspath(config))
+    manifest = Manifest(spec=spec, cwd=cwd, complete=complete, config=config, **kwargs)
     return manifest
 
+def find_repo(path):
+    path = os.path.dirname(os.path.abspath(path))
+    while path != os.path.dirname(path):
+        if os.path.exists(os.path.join(path, '.git')):
+            return path
+        path = os.path.dirname(path)
+
+def write_output(path, text, yes=False):
+    repo = find_repo(path)
+    if repo:
+        exitcode, _, _ = call(f'git -C "{repo}" check-ignore -q "{os.path.abspath(path)}"', throw=False)
+        if exitcode:
+            warn(f'{path} is inside git repo {repo} and not ignored; the generated script could be committed')
+            if not yes and input('write anyway? [y/N] ').strip().lower() != 'y':
+                sys.exit('aborted')
+    tmp = None
+    try:
+        fd, tmp = mkstemp(dir=os.path.dirname(os.path.abspath(path)), prefix=f'.{os.path.basename(path)}.')
+        with os.fdopen(fd, 'w') as f:
+            f.write(text + '\n')
+        os.chmod(tmp, 0o755)
+        os.replace(tmp, path)
+    except OSError as e:
+        if tmp and os.path.exists(tmp):
+            os.unlink(tmp)
+        sys.exit(f'failed to write generated script to {path}: {e}')
+
+def init_manifest(path, force=False):
+    if os.path.exists(path) and not force:
+        sys.exit(f'{path} already exists; use --force to overwrite')
+    with open(path, 'w') as f:
+        f.write(INIT)
+    print(f'wrote starter manifest to {path}')
+
+def describe(action):
+    description = re.sub(r'^default=[^;]*;\s*', '', action.help or '')
+    return description.replace("'", '').replace('[', '(').replace(']', ')')
+
+def render_completions(parser, shell, prog='manifest'):
+    actions = [action for action in parser._actions if action.option_strings]
+    if shell == 'bash':
+        words = ' '.join(opt for action in actions for opt in action.option_strings)
+        return f'complete -o default -W "{words}" {prog}'
+    if shell == 'zsh':
+        specs = [
+            f"    '{opt}[{describe(action)}]'"
+            for action in actions for opt in action.option_strings
+        ]
+        return f'#compdef {prog}\n_arguments \\\n' + ' \\\n'.join(specs)
+    lines = []
+    for action in actions:
+        flags = ''.join(
+            f' -s {opt[1:]}' if not opt.startswith('--') else f' -l {opt[2:]}'
+            for opt in action.option_strings)
+        lines += [f"complete -c {prog}{flags} -d '{describe(action)}'"]
+    return '\n'.join(lines)
+
 def complete(ns):
-    return not any([getattr(ns, sec) for sec in SECTIONS])
+    return ns.all or not any([getattr(ns, sec) for sec in SECTIONS])
 
 class ManifestAction(Action):
     def __call__(self, parser, namespace, values, option_strings=None):
@@ -536,26 +1663,54 @@ def main(args):
     parser = ArgumentParser()
     parser.add_argument(
         '-C', '--config',
-        default=f'{REAL_PATH}/manifest.yml',
-        help='default="%(default)s"; specify the config path')
+        help='specify the config path; otherwise searched in XDG_CONFIG_HOME, ~/.config/manifest, . and next to manifest.py')
+    parser.add_argument(
+        '--base',
+        metavar='PATH',
+        help='load a base manifest from PATH; sections in the config override it')
     parser.add_argument(
         '-R', '--reponame',
         default='scottidler/setup-public',
-        help='default="%(default)s"; specify the github reponame')
+        help='default="%(default)s"; specify the github reponame; "" loads only the config')
     parser.add_argument(
         '-H', '--home',
         default=HOME,
         help='default="%(default)s"; specify HOME if not current')
+    parser.add_argument(
+        '-U', '--resolve-home-from',
+        metavar='USER',
+        help='use the home directory of USER from passwd; overrides --home')
+    parser.add_argument(
+        '--win-home',
+        metavar='PATH',
+        help='windows profile for $WINHOME in links; detected under WSL if not given')
     parser.add_argument(
         '-M', '--pkgmgr',
-        default=get_pkgmgr(),
-        help=f'default="%(default)s"; override pkgmgr')
+        default='auto',
+        help='default="%(default)s"; "auto" detects deb, rpm, arch or brew; any other value is used as given')
+    parser.add_argument(
+        '-A', '--all',
+        action='store_true',
+        help='emit every section even when section flags are given; flagged sections keep their patterns')
     parser.add_argument(
         '-l', '--link',
         metavar='LINK',
         action=ManifestAction,
         nargs='*',
         help='specify list of glob patterns to match links')
+    parser.add_argument(
+        '--link-expansion',
+        choices=['generation', 'runtime'],
+        default='generation',
+        help='default="%(default)s"; expand recursive links now or with find on the target machine')
+    parser.add_argument(
+        '--skip-existing-links',
+        action='store_true',
+        help='never replace a real file with a link; skip it instead')
+    parser.add_argument(
+        '--force-links',
+        action='store_true',
+        help='replace real files with links even with --skip-existing-links')
     parser.add_argument(
         '-p', '--ppa',
         action=ManifestAction,
@@ -571,6 +1726,16 @@ def main(args):
         action=ManifestAction,
         nargs='*',
         help='specify list of glob patterns to match dnf items')
+    parser.add_argument(
+        '-b', '--brew',
+        action=ManifestAction,
+        nargs='*',
+        help='specify list of glob patterns to match brew items')
+    parser.add_argument(
+        '--pacman',
+        action=ManifestAction,
+        nargs='*',
+        help='specify list of glob patterns to match pacman items')
     parser.add_argument(
         '-n', '--npm',
         action=ManifestAction,
@@ -602,10 +1767,182 @@ def main(args):
         action=ManifestAction,
         nargs='*',
         help='specify list of glob patterns to match script names')
+    parser.add_argument(
+        '-e', '--exclude',
+        metavar='PATTERN',
+        action='append',
+        help='glob pattern of items, repos or scripts to drop after matching; repeatable, and wins over includes')
+    parser.add_argument(
+        '--only-managers',
+        metavar='NAMES',
+        type=parse_managers,
+        help='comma-separated package managers to emit (e.g. pipx,npm); other package sections are skipped')
+    parser.add_argument(
+        '--check',
+        action='store_true',
+        help='skip packages that are already installed instead of reinstalling them')
+    parser.add_argument(
+        '--chunk-size',
+        metavar='N',
+        type=positive_int,
+        help='split apt/dnf installs into commands of at most N packages')
+    parser.add_argument(
+        '--section-parallel',
+        metavar='JOBS',
+        type=positive_int,
+        help='install up to JOBS pipx items at once as background jobs')
+    parser.add_argument(
+        '--timeout-per-section',
+        metavar='SECONDS',
+        type=positive_int,
+        help='kill any section that runs longer than SECONDS and continue with the next; each section runs in its own bash, so isolated: False scripts are rejected')
+    parser.add_argument(
+        '--dedup',
+        action='store_true',
+        help='drop duplicate items within a section instead of only warning')
+    parser.add_argument(
+        '--strict',
+        action='store_true',
+        help='error instead of warn when the active pkgmgr section has no items')
+    parser.add_argument(
+        '--fail-on-empty',
+        action='store_true',
+        help='exit non-zero when no section has anything to install')
+    parser.add_argument(
+        '--strict-yaml',
+        action='store_true',
+        help='reject manifests containing unknown fields')
+    parser.add_argument(
+        '--no-banner',
+        action='store_true',
+        help='omit the generated-by comments from the header')
+    parser.add_argument(
+        '--banner',
+        metavar='TEXT',
+        help='echo TEXT at the start of the generated script')
+    parser.add_argument(
+        '--emit-metadata-header',
+        action='store_true',
+        help='add manifest path, version, hostname and timestamp to the header')
+    parser.add_argument(
+        '-o', '--output',
+        metavar='PATH',
+        help='write the generated script to PATH (mode 755) instead of stdout; "-" means stdout')
+    parser.add_argument(
+        '--yes',
+        action='store_true',
+        help='do not prompt before writing --output into a git repo where it is not ignored')
+    parser.add_argument(
+        '--print-plan',
+        action='store_true',
+        help='print a table of the resolved sections instead of the script')
+    parser.add_argument(
+        '--merge-preview',
+        action='store_true',
+        help='print the merged manifest and the files it came from and exit')
+    parser.add_argument(
+        '--stats-json',
+        action='store_true',
+        help='write a one-line json summary of section counts and duration to stderr')
+    parser.add_argument(
+        '--dry-run',
+        action='store_true',
+        help='list the items left in each section after matching instead of the script')
+    parser.add_argument(
+        '-v', '--verbose',
+        action='store_true',
+        help='with --dry-run, also list every resolved link source and dest')
+    parser.add_argument(
+        '--doctor',
+        action='store_true',
+        help='report which tools the populated sections need and whether they are installed')
+    parser.add_argument(
+        '--dump-resolved-links',
+        action='store_true',
+        help='print the resolved link source and dest pairs as json instead of the script')
+    parser.add_argument(
+        '--show-effective-pkgmgr-section',
+        action='store_true',
+        help='print the merged pkg and pkgmgr items before glob filtering instead of the script')
+    parser.add_argument(
+        '--render-only',
+        metavar='SECTION',
+        choices=list(SECTIONS),
+        help='print only the named section and the functions it needs')
+    parser.add_argument(
+        '--explain-pkgmgr',
+        action='store_true',
+        help='show how the pkgmgr was detected and exit')
+    parser.add_argument(
+        '--seed-from-installed',
+        metavar='PKGMGR',
+        choices=['apt'],
+        help='print a manifest block of manually-installed packages and exit')
+    parser.add_argument(
+        '--init',
+        metavar='PATH',
+        nargs='?',
+        const='manifest.yml',
+        help='write a starter manifest to PATH (default="manifest.yml") and exit')
+    parser.add_argument(
+        '--force',
+        action='store_true',
+        help='allow --init to overwrite an existing file')
+    parser.add_argument(
+        '--completions',
+        metavar='SHELL',
+        choices=['bash', 'zsh', 'fish'],
+        help='print a completion script for SHELL (bash, zsh or fish) and exit')
     ns = parser.parse_args()
+    if ns.completions:
+        print(render_completions(parser, ns.completions))
+        return
+    if ns.resolve_home_from:
+        ns.home = resolve_home(ns.resolve_home_from)
+    ns.win_home = ns.win_home or resolve_winhome()
+    if ns.init:
+        init_manifest(ns.init, force=ns.force)
+        return
+    if ns.explain_pkgmgr:
+        print(explain_pkgmgr())
+        return
+    if ns.seed_from_installed:
+        print(seed_from_installed(ns.seed_from_installed))
+        return
+    ns.config = find_config(ns.config)
+    if ns.pkgmgr == 'auto':
+        ns.pkgmgr = get_pkgmgr()
+    if ns.merge_preview:
+        print(merge_preview(ns.config, ns.reponame, ns.base))
+        return
+    start = time.monotonic()
     manifest = load_manifest(complete=complete(ns), **ns.__dict__)
+    if ns.fail_on_empty and not any(section.names() for section in manifest.sections):
+        sys.exit(f'manifest {ns.config} resolved to an empty plan for pkgmgr={ns.pkgmgr}')
     try:
-        print(manifest.render())
+        if ns.print_plan:
+            print(manifest.render_plan())
+        elif ns.dry_run:
+            print(manifest.render_dry_run(verbose=ns.verbose))
+        elif ns.doctor:
+            print(manifest.render_doctor())
+        elif ns.dump_resolved_links:
+            print(json.dumps(manifest.resolve_links(), indent=2))
+        elif ns.show_effective_pkgmgr_section:
+            print(manifest.render_effective())
+        elif ns.render_only:
+            print(manifest.render_section(ns.render_only))
+        else:
+            result = manifest.build()
+            if ns.output and ns.output != '-':
+                write_output(ns.output, result['script'], yes=ns.yes)
+            else:
+                print(result['script'])
+            if ns.stats_json:
+                stats = dict(sections=len(result['sections']))
+                stats.update({summary['section']: summary['count'] for summary in result['sections']})
+                stats['duration_ms'] = int((time.monotonic() - start) * 1000)
+                sys.stderr.write(json.dumps(stats) + '\n')
         sys.stdout.flush()
     except IOError:
         sys.stderr.write("on running: " + str(sys.exc_info()))